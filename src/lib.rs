@@ -1,25 +1,38 @@
 //! A parser for the [Scala](https://www.huygens-fokker.org/scala/) file format.
 
 use nom::{
-    bytes::streaming::{tag, take_until},
-    character::streaming::{newline, space0},
+    branch::alt,
+    bytes::complete::{tag, take_until},
+    character::complete::{newline, space0},
+    combinator::rest,
+    error::{context, VerboseError, VerboseErrorKind},
     multi::count,
     multi::many0,
-    number::streaming::float,
-    sequence::tuple,
-    IResult,
+    number::complete::float,
+    sequence::{terminated, tuple},
+    IResult, Offset,
 };
 
-fn parse_scala<'a>(scala_text: &'a impl AsRef<str>) -> IResult<&'a str, Scale> {
-    let i = scala_text.as_ref();
+type ParseResult<'a, O> = IResult<&'a str, O, VerboseError<&'a str>>;
 
-    let (i, (_comments, description)) = tuple((many_comments, take_line))(i)?;
-    let (i, (_comments, _, note_count)) = tuple((many_comments, space0, num_u64))(i)?;
-    let (i, notes) = count(tuple((many_comments, note)), note_count as usize)(i)?;
+fn parse_scala<'a>(scala_text: &'a impl AsRef<str>) -> ParseResult<'a, Scale> {
+    let i = scala_text.as_ref();
 
-    let notes = notes.into_iter().map(|(_, note)| note).collect();
+    let (i, (comments, description)) = tuple((many_comments, take_line))(i)?;
+    let (i, (more_comments, _, note_count)) = tuple((
+        many_comments,
+        space0,
+        context("expected note count", num_u64),
+    ))(i)?;
+    let (i, _) = take_line(i)?;
+    let (i, notes) = count(note, note_count as usize)(i)?;
 
     let scale = Scale {
+        comments: comments
+            .into_iter()
+            .chain(more_comments)
+            .map(|c| c.to_string())
+            .collect(),
         description: description.to_string(),
         notes,
     };
@@ -27,83 +40,380 @@ fn parse_scala<'a>(scala_text: &'a impl AsRef<str>) -> IResult<&'a str, Scale> {
     Ok((i, scale))
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Scale {
+    /// Comment lines (without the leading `!`) found before the note
+    /// count, both above and below the description line, such as
+    /// authorship or source archive IDs.
+    pub comments: Vec<String>,
     pub description: String,
     pub notes: Vec<Note>,
 }
 
 impl Scale {
+    /// Look up a conventional `! key: value` comment line from the
+    /// header comments in [`Scale::comments`], matching `key`
+    /// case-insensitively.
+    pub fn comment_tag(&self, key: &str) -> Option<&str> {
+        self.comments.iter().find_map(|line| {
+            let (tag, value) = line.split_once(':')?;
+            tag.trim().eq_ignore_ascii_case(key).then(|| value.trim())
+        })
+    }
+
     /// Parse a Scale from a Scala file.
     ///
     /// # Note
     /// Many Scala files found online, specifically in the Scala archive,
-    /// are encoded in ISO-8859-1. You will likely need to unsure such cases
-    /// are decoded into UTF8 in order to read these files to a string.
+    /// are encoded in ISO-8859-1. If you have raw bytes rather than an
+    /// already-decoded `String`, prefer [`Scale::from_bytes`], which handles
+    /// that encoding for you.
     pub fn from_str<'a>(input: &'a impl AsRef<str>) -> Result<Scale, Error> {
-        let res = parse_scala(input);
+        let input = input.as_ref();
+
+        match parse_scala(&input) {
+            Ok((_, scale)) => Ok(scale),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                Err(Error::Parse(ParseError::from_verbose(input, e)))
+            }
+            Err(nom::Err::Incomplete(_)) => Err(Error::Parse(ParseError::from_offset(
+                input,
+                input.len(),
+                "unexpected end of input".to_string(),
+            ))),
+        }
+    }
+
+    /// Parse a Scale from raw Scala file bytes.
+    ///
+    /// This first attempts to decode `input` as UTF-8. Most files in the
+    /// Scala archive predate UTF-8 adoption and are encoded in ISO-8859-1
+    /// instead, so on UTF-8 failure this falls back to transcoding the
+    /// bytes as ISO-8859-1, where every byte maps directly to the Unicode
+    /// code point of the same value.
+    pub fn from_bytes(input: &[u8]) -> Result<Scale, Error> {
+        let text = match std::str::from_utf8(input) {
+            Ok(text) => text.to_string(),
+            Err(_) => decode_iso_8859_1(input),
+        };
+
+        Scale::from_str(&text)
+    }
+
+    /// Each note's pitch expressed in cents relative to the scale's 1/1.
+    pub fn cents(&self) -> Vec<f64> {
+        self.notes.iter().map(Note::cents).collect()
+    }
+
+    /// Each note's pitch as a frequency in Hz, given `base_hz` as the
+    /// frequency of the scale's 1/1.
+    ///
+    /// This covers exactly one period (one entry per note in the file). To
+    /// look up a scale degree beyond that, e.g. to build a full keyboard
+    /// tuning table, use [`Scale::frequency_for_degree`] instead, which
+    /// wraps using the last note as the repeat interval.
+    pub fn frequencies(&self, base_hz: f64) -> Vec<f64> {
+        self.cents()
+            .into_iter()
+            .map(|cents| base_hz * 2f64.powf(cents / 1200.0))
+            .collect()
+    }
+
+    /// The frequency in Hz of scale degree `degree`, given `base_hz` as the
+    /// frequency of the scale's 1/1.
+    ///
+    /// Degree `0` is the 1/1 itself, and degrees `1..=self.notes.len()`
+    /// are the notes listed in the file, with the last being the scale's
+    /// period (typically the octave). Degrees outside that range, including
+    /// negative ones, wrap around by adding whole multiples of the
+    /// period's cents.
+    pub fn frequency_for_degree(&self, degree: i64, base_hz: f64) -> f64 {
+        let Some(period_cents) = self.notes.last().map(Note::cents) else {
+            return base_hz;
+        };
+
+        let len = self.notes.len() as i64;
+        let normalized = degree - 1;
+        let period_index = normalized.div_euclid(len);
+        let index = normalized.rem_euclid(len) as usize;
+
+        let cents = self.notes[index].cents() + period_index as f64 * period_cents;
+        base_hz * 2f64.powf(cents / 1200.0)
+    }
 
-        match res {
-            Ok(s) => Ok(s.1),
-            Err(e) => Err(Error(e.to_string())),
+    /// Render this scale back into Scala `.scl` file text.
+    ///
+    /// Parsing the result with [`Scale::from_str`] yields an equivalent
+    /// `Scale`.
+    pub fn to_scala_string(&self) -> String {
+        let mut out = String::new();
+
+        for comment in &self.comments {
+            write_comment_line(&mut out, comment);
+        }
+
+        out.push_str(&self.description);
+        out.push('\n');
+        out.push_str(&format!(" {}\n", self.notes.len()));
+
+        for note in &self.notes {
+            for comment in &note.comments {
+                write_comment_line(&mut out, comment);
+            }
+            out.push_str(&note.pitch.to_scala_string());
+            out.push('\n');
         }
+
+        out
+    }
+}
+
+fn write_comment_line(out: &mut String, comment: &str) {
+    out.push('!');
+    if !comment.is_empty() {
+        out.push(' ');
+        out.push_str(comment);
     }
+    out.push('\n');
 }
 
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_scala_string())
+    }
+}
+
+/// An error produced while parsing a Scala file.
 #[derive(Debug)]
-pub struct Error(String);
+pub enum Error {
+    /// The input could not be parsed as a valid Scala file.
+    Parse(ParseError),
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.0)
+        match self {
+            Error::Parse(e) => e.fmt(f),
+        }
     }
 }
 
 impl std::error::Error for Error {}
 
-#[derive(Clone, Debug)]
-pub enum Note {
+/// A structured parse error pointing at the byte offset in the input that
+/// defeated the parser.
+#[derive(Debug)]
+pub struct ParseError {
+    /// Byte offset into the original input where parsing failed.
+    pub offset: usize,
+    /// 1-based line number of `offset`.
+    pub line: usize,
+    /// 1-based column number of `offset`.
+    pub column: usize,
+    /// Short human-readable description of what went wrong.
+    pub message: String,
+    line_text: String,
+}
+
+impl ParseError {
+    fn from_offset(input: &str, offset: usize, message: String) -> ParseError {
+        let (line, column, line_text) = locate(input, offset);
+
+        ParseError {
+            offset,
+            line,
+            column,
+            message,
+            line_text,
+        }
+    }
+
+    fn from_verbose(input: &str, e: VerboseError<&str>) -> ParseError {
+        let (remaining, message) = e
+            .errors
+            .iter()
+            .find_map(|(i, kind)| match kind {
+                VerboseErrorKind::Context(ctx) => Some((*i, ctx.to_string())),
+                _ => None,
+            })
+            .or_else(|| {
+                e.errors
+                    .first()
+                    .map(|(i, _)| (*i, "malformed scala file".to_string()))
+            })
+            .unwrap_or((input, "malformed scala file".to_string()));
+
+        let offset = input.offset(remaining);
+
+        ParseError::from_offset(input, offset, message)
+    }
+}
+
+/// Walk `input` up to `offset` to find the 1-based line/column of `offset`
+/// and the text of the line it falls on.
+fn locate(input: &str, offset: usize) -> (usize, usize, String) {
+    let offset = offset.min(input.len());
+
+    let line_start = input[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_number = input[..offset].matches('\n').count() + 1;
+    let column = offset - line_start + 1;
+
+    let line_end = input[offset..]
+        .find('\n')
+        .map(|i| offset + i)
+        .unwrap_or_else(|| input.len());
+    let line_text = input[line_start..line_end].to_string();
+
+    (line_number, column, line_text)
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )?;
+        writeln!(f, "{}", self.line_text)?;
+        write!(f, "{}^", " ".repeat(self.column.saturating_sub(1)))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Decode `input` as ISO-8859-1, where every byte maps directly to the
+/// Unicode code point of the same value.
+fn decode_iso_8859_1(input: &[u8]) -> String {
+    input.iter().map(|&b| b as char).collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    /// Comment lines (without the leading `!`) immediately preceding this
+    /// note, such as a per-degree label.
+    pub comments: Vec<String>,
+    pub pitch: Pitch,
+}
+
+impl Note {
+    /// This note's pitch expressed in cents.
+    pub fn cents(&self) -> f64 {
+        self.pitch.cents()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pitch {
     Ratio { numerator: u64, denominator: u64 },
     Cents(f32),
 }
 
-fn note(i: &str) -> IResult<&str, Note> {
-    let (i, _) = nom::combinator::opt(nom::character::streaming::space0)(i)?;
-    nom::branch::alt((note_cents, note_ratio))(i)
+impl Pitch {
+    /// This pitch expressed in cents, i.e. `1200 * log2(frequency ratio)`.
+    pub fn cents(&self) -> f64 {
+        match self {
+            Pitch::Cents(cents) => *cents as f64,
+            Pitch::Ratio {
+                numerator,
+                denominator,
+            } => {
+                // Reduce by the gcd first so large archive ratios don't lose
+                // precision to f64 rounding before the logarithm.
+                let divisor = gcd(*numerator, *denominator);
+                if divisor == 0 {
+                    // Only 0/0 has a zero gcd; treat it as the unison.
+                    return 0.0;
+                }
+                let numerator = numerator / divisor;
+                let denominator = denominator / divisor;
+
+                1200.0 * (numerator as f64 / denominator as f64).log2()
+            }
+        }
+    }
+
+    /// Render this pitch the way a `.scl` file would: `num/den` for a
+    /// ratio, or a decimal with the point preserved for cents (`700.0`,
+    /// never `700`, which the format would read back as a ratio).
+    fn to_scala_string(&self) -> String {
+        match self {
+            Pitch::Ratio {
+                numerator,
+                denominator,
+            } => format!("{numerator}/{denominator}"),
+            Pitch::Cents(cents) => {
+                let rendered = cents.to_string();
+                if rendered.contains('.') {
+                    rendered
+                } else {
+                    format!("{rendered}.0")
+                }
+            }
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn note(i: &str) -> ParseResult<'_, Note> {
+    let (i, comments) = many_comments(i)?;
+    let (i, pitch) = pitch(i)?;
+
+    let note = Note {
+        comments: comments.into_iter().map(|c| c.to_string()).collect(),
+        pitch,
+    };
+
+    Ok((i, note))
+}
+
+fn pitch(i: &str) -> ParseResult<'_, Pitch> {
+    let (i, _) = space0(i)?;
+    // Ratios are tried first: a bare integer like "2" is also a valid
+    // (integer-valued) float, so if cents were tried first it would always
+    // win and silently swallow the "/1" half of a ratio as line garbage.
+    context("unexpected non-numeric token", alt((pitch_ratio, pitch_cents)))(i)
 }
 
-fn note_cents(i: &str) -> IResult<&str, Note> {
+fn pitch_cents(i: &str) -> ParseResult<'_, Pitch> {
     let (i, f) = float(i)?;
 
     let (i, _) = take_line(i)?;
 
-    Ok((i, Note::Cents(f)))
+    Ok((i, Pitch::Cents(f)))
 }
 
-fn note_ratio(i: &str) -> IResult<&str, Note> {
-    let (i, (numerator, _, denominator)) = tuple((num_u64, tag("/"), num_u64))(i)?;
+fn pitch_ratio(i: &str) -> ParseResult<'_, Pitch> {
+    let (i, (numerator, _, denominator)) =
+        context("malformed ratio", tuple((num_u64, tag("/"), num_u64)))(i)?;
+
+    let (i, _) = take_line(i)?;
 
-    let note = Note::Ratio {
+    let pitch = Pitch::Ratio {
         numerator,
         denominator,
     };
 
-    Ok((i, note))
+    Ok((i, pitch))
 }
 
-fn num_u64(i: &str) -> IResult<&str, u64> {
+fn num_u64(i: &str) -> ParseResult<'_, u64> {
     // A scale probably wont ever have more precision than two u64::MAX
     // and any number below 0 in a ratio or note count is an error in scalas format.
-    let (_, number) = nom::character::streaming::u64(i)?;
-
-    Ok((i, number))
+    nom::character::complete::u64(i)
 }
 
-fn many_comments(i: &str) -> IResult<&str, Vec<&str>> {
+fn many_comments(i: &str) -> ParseResult<'_, Vec<&str>> {
     many0(comment)(i)
 }
 
-fn comment(i: &str) -> IResult<&str, &str> {
+fn comment(i: &str) -> ParseResult<'_, &str> {
     let (i, _) = tag("!")(i)?;
 
     let (i, comment) = take_line(i)?;
@@ -111,9 +421,10 @@ fn comment(i: &str) -> IResult<&str, &str> {
     Ok((i, comment))
 }
 
-fn take_line(i: &str) -> IResult<&str, &str> {
-    let (i, line) = take_until("\n")(i)?;
-    let (i, _) = nom::combinator::opt(newline)(i)?;
+fn take_line(i: &str) -> ParseResult<'_, &str> {
+    // A file's last line (final note or trailing comment) may not have a
+    // terminating newline at all, so fall back to the rest of the input.
+    let (i, line) = alt((terminated(take_until("\n"), newline), rest))(i)?;
 
     Ok((i, line.trim()))
 }
@@ -122,7 +433,148 @@ fn take_line(i: &str) -> IResult<&str, &str> {
 mod tests {
     use std::{io::Read, path::PathBuf};
 
-    use crate::parse_scala;
+    use crate::{parse_scala, Error, Note, Pitch, Scale};
+
+    #[test]
+    fn test_parse_error_reports_line_and_column() {
+        let err = Scale::from_str(&"Test scale\n 1\nnotanote\n").unwrap_err();
+
+        let Error::Parse(parse_err) = err;
+        assert_eq!(parse_err.line, 3);
+        assert_eq!(parse_err.column, 1);
+        assert_eq!(parse_err.message, "unexpected non-numeric token");
+    }
+
+    #[test]
+    fn test_from_bytes_falls_back_to_iso_8859_1() {
+        // "Caf\xe9" (ISO-8859-1 for "Café"), not valid UTF-8.
+        let input = b"! Caf\xe9\nCaf\xe9 scale\n 1\n100.0\n";
+
+        let scale = Scale::from_bytes(input).unwrap();
+        assert_eq!(scale.comments, vec!["Café"]);
+        assert_eq!(scale.description, "Café scale");
+    }
+
+    #[test]
+    fn test_comment_tag_is_case_insensitive() {
+        let scale = Scale::from_str(&"! Source: Scala archive\nTest scale\n 1\n100.0\n").unwrap();
+
+        assert_eq!(scale.comment_tag("source"), Some("Scala archive"));
+        assert_eq!(scale.comment_tag("SOURCE"), Some("Scala archive"));
+        assert_eq!(scale.comment_tag("missing"), None);
+    }
+
+    #[test]
+    fn test_comment_between_description_and_note_count_is_preserved() {
+        let scale = Scale::from_str(&"Test scale\n! Source: foo\n 1\n100.0\n").unwrap();
+
+        assert_eq!(scale.comment_tag("Source"), Some("foo"));
+    }
+
+    #[test]
+    fn test_cents_and_frequencies() {
+        let scale = Scale::from_str(&"Test scale\n 2\n700.0\n2/1\n").unwrap();
+
+        let cents = scale.cents();
+        assert_eq!(cents[0], 700.0);
+        assert_eq!(cents[1], 1200.0);
+
+        let frequencies = scale.frequencies(440.0);
+        assert!((frequencies[0] - 440.0 * 2f64.powf(700.0 / 1200.0)).abs() < 1e-9);
+        assert!((frequencies[1] - 880.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cents_does_not_panic_on_zero_ratio() {
+        let scale = Scale::from_str(&"Test scale\n 1\n0/0\n").unwrap();
+
+        assert_eq!(scale.cents(), vec![0.0]);
+    }
+
+    #[test]
+    fn test_frequency_for_degree_wraps_on_period() {
+        let scale = Scale::from_str(&"Test scale\n 2\n700.0\n2/1\n").unwrap();
+
+        assert_eq!(scale.frequency_for_degree(0, 440.0), 440.0);
+        assert_eq!(scale.frequency_for_degree(2, 440.0), 880.0);
+        assert!((scale.frequency_for_degree(4, 440.0) - 1760.0).abs() < 1e-9);
+        assert!((scale.frequency_for_degree(-2, 440.0) - 220.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_round_trip_literal() {
+        let text = "! test.scl\nSimple 3-note scale\n 3\n100.0\n200.0\n2/1\n";
+
+        let scale = Scale::from_str(&text).unwrap();
+        assert_eq!(
+            scale.notes,
+            vec![
+                Note {
+                    comments: vec![],
+                    pitch: Pitch::Cents(100.0)
+                },
+                Note {
+                    comments: vec![],
+                    pitch: Pitch::Cents(200.0)
+                },
+                Note {
+                    comments: vec![],
+                    pitch: Pitch::Ratio {
+                        numerator: 2,
+                        denominator: 1
+                    }
+                },
+            ]
+        );
+
+        let rendered = scale.to_scala_string();
+        let reparsed = Scale::from_str(&rendered).unwrap();
+        assert_eq!(scale, reparsed, "round trip mismatch for {rendered:?}");
+    }
+
+    #[test]
+    fn test_parses_without_trailing_newline() {
+        let scale = Scale::from_str(&"Test scale\n 2\n100.0\n2/1").unwrap();
+
+        assert_eq!(
+            scale.notes,
+            vec![
+                Note {
+                    comments: vec![],
+                    pitch: Pitch::Cents(100.0)
+                },
+                Note {
+                    comments: vec![],
+                    pitch: Pitch::Ratio {
+                        numerator: 2,
+                        denominator: 1
+                    }
+                },
+            ]
+        );
+    }
+
+    fn scl_paths() -> Vec<PathBuf> {
+        let dir = std::fs::read_dir("./scl").unwrap();
+        dir.filter_map(|f| -> Option<PathBuf> {
+            let path = f.unwrap().path();
+            if let Some(e) = path.extension() {
+                if e == "scl" {
+                    return Some(path);
+                }
+            }
+            None
+        })
+        .collect()
+    }
+
+    fn read_to_string(path: &std::path::Path) -> String {
+        let mut text = String::new();
+        std::io::BufReader::new(std::fs::File::open(path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
 
     #[test]
     fn test_all_scl() {
@@ -131,26 +583,24 @@ mod tests {
         //
         // downloadable here:
         // https://www.huygens-fokker.org/scala/downloads.html#scales
-        let dir = std::fs::read_dir("./scl").unwrap();
-        let paths: Vec<PathBuf> = dir
-            .filter_map(|f| -> Option<PathBuf> {
-                let path = f.unwrap().path();
-                if let Some(e) = path.extension() {
-                    if e == "scl" {
-                        return Some(path);
-                    }
-                }
-                None
-            })
-            .collect();
-
-        for path in paths {
-            let mut text = String::new();
-            std::io::BufReader::new(std::fs::File::open(path).unwrap())
-                .read_to_string(&mut text)
-                .unwrap();
+        for path in scl_paths() {
+            let text = read_to_string(&path);
 
             assert!(parse_scala(&text).is_ok());
         }
     }
+
+    #[test]
+    fn test_round_trip_scl() {
+        // see test_all_scl for how to populate the ./scl archive directory
+        for path in scl_paths() {
+            let text = read_to_string(&path);
+
+            let scale = Scale::from_str(&text).unwrap();
+            let rendered = scale.to_scala_string();
+            let reparsed = Scale::from_str(&rendered).unwrap();
+
+            assert_eq!(scale, reparsed, "round trip mismatch for {path:?}");
+        }
+    }
 }